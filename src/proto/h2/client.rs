@@ -1,10 +1,14 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use futures_channel::{mpsc, oneshot};
 use futures_util::future::{self, Either, FutureExt as _, TryFutureExt as _};
 use futures_util::stream::StreamExt as _;
 use h2::client::{Builder, SendRequest};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use super::{bdp, decode_content_length, PipeToSendStream, SendBuf};
+use super::{decode_content_length, ping, PipeToSendStream, SendBuf};
 use crate::body::Payload;
 use crate::common::{task, Exec, Future, Never, Pin, Poll};
 use crate::headers;
@@ -18,20 +22,37 @@ type ClientRx<B> = crate::client::dispatch::Receiver<Request<B>, Response<Body>>
 type ConnDropRef = mpsc::Sender<Never>;
 
 ///// A oneshot channel watches the `Connection` task, and when it completes,
-///// the "dispatch" task will be notified and can shutdown sooner.
-type ConnEof = oneshot::Receiver<Never>;
+///// the "dispatch" task will be notified and can shutdown sooner. If the
+///// connection task ended because of an error (such as a keep-alive
+///// timeout), that error is sent along so `ClientTask::poll` can surface it.
+type ConnEof = oneshot::Receiver<crate::Error>;
 
 // Our defaults are chosen for the "majority" case, which usually are not
 // resource constrained, and so the spec default of 64kb can be too limiting
 // for performance.
 const DEFAULT_CONN_WINDOW: u32 = 1024 * 1024 * 5; // 5mb
 const DEFAULT_STREAM_WINDOW: u32 = 1024 * 1024 * 2; // 2mb
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+// The spec default (and the smallest allowed value) for SETTINGS_MAX_FRAME_SIZE.
+const DEFAULT_MAX_FRAME_SIZE: u32 = 1024 * 16;
+// The spec default for SETTINGS_HEADER_TABLE_SIZE.
+const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+// The spec leaves SETTINGS_MAX_HEADER_LIST_SIZE unbounded by default; pick a
+// finite value so a misbehaving server can't force unbounded header memory.
+const DEFAULT_MAX_HEADER_LIST_SIZE: u32 = 1024 * 1024 * 16; // 16mb
 
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
     pub(crate) adaptive_window: bool,
     pub(crate) initial_conn_window_size: u32,
     pub(crate) initial_stream_window_size: u32,
+    pub(crate) keep_alive_interval: Option<Duration>,
+    pub(crate) keep_alive_timeout: Duration,
+    pub(crate) keep_alive_while_idle: bool,
+    pub(crate) max_concurrent_streams: Option<u32>,
+    pub(crate) max_frame_size: u32,
+    pub(crate) max_header_list_size: u32,
+    pub(crate) header_table_size: u32,
 }
 
 impl Default for Config {
@@ -40,6 +61,13 @@ impl Default for Config {
             adaptive_window: false,
             initial_conn_window_size: DEFAULT_CONN_WINDOW,
             initial_stream_window_size: DEFAULT_STREAM_WINDOW,
+            keep_alive_interval: None,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            keep_alive_while_idle: false,
+            max_concurrent_streams: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: DEFAULT_MAX_HEADER_LIST_SIZE,
+            header_table_size: DEFAULT_HEADER_TABLE_SIZE,
         }
     }
 }
@@ -54,10 +82,18 @@ where
     T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     B: Payload,
 {
-    let (h2_tx, mut conn) = Builder::default()
+    let mut builder = Builder::default();
+    builder
         .initial_window_size(config.initial_stream_window_size)
         .initial_connection_window_size(config.initial_conn_window_size)
-        .enable_push(false)
+        .max_frame_size(config.max_frame_size)
+        .max_header_list_size(config.max_header_list_size)
+        .header_table_size(config.header_table_size)
+        .enable_push(false);
+    if let Some(max) = config.max_concurrent_streams {
+        builder.max_concurrent_streams(max);
+    }
+    let (h2_tx, mut conn) = builder
         .handshake::<_, SendBuf<B::Data>>(io)
         .await
         .map_err(crate::Error::new_h2)?;
@@ -75,34 +111,64 @@ where
         }
     });
 
-    let sampler = if config.adaptive_window {
-        let (sampler, mut estimator) =
-            bdp::channel(conn.ping_pong().unwrap(), config.initial_stream_window_size);
+    let ping_config = ping::Config {
+        bdp_initial_window: if config.adaptive_window {
+            Some(config.initial_stream_window_size)
+        } else {
+            None
+        },
+        keep_alive_interval: config.keep_alive_interval,
+        keep_alive_timeout: config.keep_alive_timeout,
+        keep_alive_while_idle: config.keep_alive_while_idle,
+    };
+
+    let ping = if ping_config.is_enabled() {
+        let (recorder, mut ponger) = ping::channel(conn.ping_pong().unwrap(), ping_config);
 
         let conn = future::poll_fn(move |cx| {
-            match estimator.poll_estimate(cx) {
-                Poll::Ready(wnd) => {
+            match ponger.poll(cx) {
+                Poll::Ready(ping::Ponged::SizeUpdate(wnd)) => {
                     conn.set_target_window_size(wnd);
-                    conn.set_initial_window_size(wnd)?;
+                    if let Err(e) = conn.set_initial_window_size(wnd) {
+                        return Poll::Ready(Err(crate::Error::new_h2(e)));
+                    }
+                }
+                Poll::Ready(ping::Ponged::KeepAliveTimedOut) => {
+                    debug!("connection keep-alive timed out");
+                    return Poll::Ready(Err(crate::Error::new_io(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        ping::KeepAliveTimedOut,
+                    ))));
                 }
                 Poll::Pending => {}
             }
 
-            Pin::new(&mut conn).poll(cx)
+            match Pin::new(&mut conn).poll(cx) {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+                Poll::Ready(Err(e)) => {
+                    debug!("connection error: {}", e);
+                    Poll::Ready(Ok(()))
+                }
+                Poll::Pending => Poll::Pending,
+            }
         });
-        let conn = conn.map_err(|e| debug!("connection error: {}", e));
 
         exec.execute(conn_task(conn, conn_drop_rx, cancel_tx));
-        sampler
+        recorder
     } else {
-        let conn = conn.map_err(|e| debug!("connection error: {}", e));
+        let conn = conn.map(|result| {
+            if let Err(e) = result {
+                debug!("connection error: {}", e);
+            }
+            Ok(())
+        });
 
         exec.execute(conn_task(conn, conn_drop_rx, cancel_tx));
-        bdp::disabled()
+        ping::disabled()
     };
 
     Ok(ClientTask {
-        bdp: sampler,
+        ping,
         conn_drop_ref,
         conn_eof,
         executor: exec,
@@ -111,14 +177,18 @@ where
     })
 }
 
-async fn conn_task<C, D>(conn: C, drop_rx: D, cancel_tx: oneshot::Sender<Never>)
+async fn conn_task<C, D>(conn: C, drop_rx: D, cancel_tx: oneshot::Sender<crate::Error>)
 where
-    C: Future + Unpin,
+    C: Future<Output = Result<(), crate::Error>> + Unpin,
     D: Future<Output = ()> + Unpin,
 {
     match future::select(conn, drop_rx).await {
-        Either::Left(_) => {
-            // ok or err, the `conn` has finished
+        Either::Left((Ok(()), _)) => {
+            // the `conn` has finished on its own, ok or (already logged) err
+        }
+        Either::Left((Err(err), _)) => {
+            trace!("connection task ending with error: {}", err);
+            let _ = cancel_tx.send(err);
         }
         Either::Right(((), conn)) => {
             // mpsc has been dropped, hopefully polling
@@ -131,11 +201,46 @@ where
     }
 }
 
+// Retryability is deliberately kept local to this module rather than added
+// as a marker on `crate::Error` itself: `crate::error` isn't part of this
+// change, and the `Option<Request<B>>` the caller already gets back on error
+// (`Some` only when the request is actually safe to resend, see
+// `rebuild_request` below) already *is* the retry signal callers act on.
+// Revisit if a caller needs to know "retryable" without also getting the
+// rebuilt request back.
+fn is_goaway_retryable(err: &::h2::Error) -> bool {
+    // The server is telling us, via RST_STREAM(REFUSED_STREAM), that it never
+    // actually processed this request -- usually because it raced a GOAWAY
+    // and the stream fell beyond the server's last accepted stream id. The
+    // request is safe to resend on a fresh connection.
+    err.reason() == Some(::h2::Reason::REFUSED_STREAM)
+}
+
+fn rebuild_request<B>(
+    parts: (
+        ::http::Method,
+        ::http::Uri,
+        ::http::Version,
+        ::http::HeaderMap,
+        ::http::Extensions,
+    ),
+    body: B,
+) -> Request<B> {
+    let (method, uri, version, headers, extensions) = parts;
+    let mut req = Request::new(body);
+    *req.method_mut() = method;
+    *req.uri_mut() = uri;
+    *req.version_mut() = version;
+    *req.headers_mut() = headers;
+    *req.extensions_mut() = extensions;
+    req
+}
+
 pub(crate) struct ClientTask<B>
 where
     B: Payload,
 {
-    bdp: bdp::Sampler,
+    ping: ping::Recorder,
     conn_drop_ref: ConnDropRef,
     conn_eof: ConnEof,
     executor: Exec,
@@ -171,6 +276,13 @@ where
                         continue;
                     }
                     let (head, body) = req.into_parts();
+                    let retry_parts = (
+                        head.method.clone(),
+                        head.uri.clone(),
+                        head.version,
+                        head.headers.clone(),
+                        head.extensions.clone(),
+                    );
                     let mut req = ::http::Request::from_parts(head, ());
                     super::strip_connection_headers(req.headers_mut(), true);
                     if let Some(len) = body.size_hint().exact() {
@@ -183,17 +295,37 @@ where
                         Ok(ok) => ok,
                         Err(err) => {
                             debug!("client send request error: {}", err);
-                            cb.send(Err((crate::Error::new_h2(err), None)));
+                            let retry = is_goaway_retryable(&err)
+                                .then(|| rebuild_request(retry_parts, body));
+                            cb.send(Err((crate::Error::new_h2(err), retry)));
                             continue;
                         }
                     };
 
-                    if !eos {
+                    // Shared between the response future and the body pipe below so that
+                    // dropping the caller's response future (e.g. on timeout) tears down
+                    // both halves of the request instead of letting the pipe run to
+                    // completion and the stream dangle until the server replies.
+                    let is_canceled = Arc::new(AtomicBool::new(false));
+
+                    // once the body is handed to the pipe below, it's gone for good; only a
+                    // request without a streaming body can be rebuilt for a retry afterward.
+                    let retry_body = if eos {
+                        Some(body)
+                    } else {
+                        let pipe_is_canceled = is_canceled.clone();
                         let mut pipe = Box::pin(PipeToSendStream::new(body, body_tx)).map(|res| {
                             if let Err(e) = res {
                                 debug!("client request body error: {}", e);
                             }
                         });
+                        let mut pipe = future::poll_fn(move |cx| {
+                            if pipe_is_canceled.load(Ordering::Relaxed) {
+                                trace!("canceling in-flight request body pipe");
+                                return Poll::Ready(());
+                            }
+                            Pin::new(&mut pipe).poll(cx)
+                        });
 
                         // eagerly see if the body pipe is ready and
                         // can thus skip allocating in the executor
@@ -208,22 +340,55 @@ where
                                 self.executor.execute(pipe);
                             }
                         }
-                    }
+                        None
+                    };
 
-                    let bdp = self.bdp.clone();
-                    let fut = fut.map(move |result| match result {
-                        Ok(res) => {
-                            let content_length = decode_content_length(res.headers());
-                            let res =
-                                res.map(|stream| crate::Body::h2(stream, content_length, bdp));
-                            Ok(res)
+                    // Held inside the closure below so it drops -- decrementing
+                    // `open_streams` -- as soon as `fut` resolves (response head
+                    // or error) or is dropped early on cancellation. The response
+                    // body that may still be streaming afterward is not counted.
+                    let request_guard = self.ping.start_request();
+                    let ping = self.ping.clone();
+                    let mut fut = Box::pin(fut.map(move |result| {
+                        let _request_guard = request_guard;
+                        match result {
+                            Ok(res) => {
+                                let content_length = decode_content_length(res.headers());
+                                let res = res
+                                    .map(|stream| crate::Body::h2(stream, content_length, ping));
+                                Ok(res)
+                            }
+                            Err(err) => {
+                                debug!("client response error: {}", err);
+                                let retry = retry_body
+                                    .filter(|_| is_goaway_retryable(&err))
+                                    .map(|body| rebuild_request(retry_parts, body));
+                                Err((crate::Error::new_h2(err), retry))
+                            }
                         }
-                        Err(err) => {
-                            debug!("client response error: {}", err);
-                            Err((crate::Error::new_h2(err), None))
+                    }));
+                    let mut cb = Some(cb);
+                    self.executor.execute(future::poll_fn(move |cx| {
+                        // `poll_canceled`, unlike `is_canceled`, registers this task's
+                        // waker with the callback so we're woken the moment the caller
+                        // drops its response future -- not just when `fut` happens to
+                        // make progress for an unrelated reason.
+                        if cb.as_mut().unwrap().poll_canceled(cx).is_ready() {
+                            trace!("response callback canceled, resetting stream with CANCEL");
+                            is_canceled.store(true, Ordering::Relaxed);
+                            // dropping `fut` here (by ending this task) resets the h2
+                            // stream; the body pipe notices `is_canceled` and stops too.
+                            return Poll::Ready(());
+                        }
+
+                        match fut.as_mut().poll(cx) {
+                            Poll::Ready(result) => {
+                                cb.take().unwrap().send(result);
+                                Poll::Ready(())
+                            }
+                            Poll::Pending => Poll::Pending,
                         }
-                    });
-                    self.executor.execute(cb.send_when(fut));
+                    }));
                     continue;
                 }
 
@@ -233,7 +398,10 @@ where
                 }
 
                 Poll::Pending => match ready!(Pin::new(&mut self.conn_eof).poll(cx)) {
-                    Ok(never) => match never {},
+                    Ok(err) => {
+                        trace!("connection task ended with error: {}", err);
+                        return Poll::Ready(Err(err));
+                    }
                     Err(_conn_is_eof) => {
                         trace!("connection task is closed, closing dispatch task");
                         return Poll::Ready(Ok(Dispatched::Shutdown));
@@ -243,3 +411,80 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::{duplex, AsyncRead, AsyncReadExt};
+
+    use super::*;
+
+    // HTTP/2 SETTINGS parameter identifiers, RFC 7540 §6.5.2.
+    const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+    const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+    const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+    const CLIENT_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+    /// Reads raw frames off the wire until the client's SETTINGS frame is
+    /// found, and returns its parameters by id.
+    async fn read_client_settings(io: &mut (impl AsyncRead + Unpin)) -> HashMap<u16, u32> {
+        let mut preface = [0u8; CLIENT_PREFACE.len()];
+        io.read_exact(&mut preface).await.expect("read preface");
+        assert_eq!(&preface[..], CLIENT_PREFACE, "client connection preface");
+
+        loop {
+            let mut header = [0u8; 9];
+            io.read_exact(&mut header).await.expect("read frame header");
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let frame_type = header[3];
+
+            let mut payload = vec![0u8; len];
+            io.read_exact(&mut payload).await.expect("read frame payload");
+
+            if frame_type != 0x4 {
+                // not SETTINGS; keep scanning (e.g. a WINDOW_UPDATE can precede it)
+                continue;
+            }
+
+            return payload
+                .chunks_exact(6)
+                .map(|entry| {
+                    let id = u16::from_be_bytes([entry[0], entry[1]]);
+                    let value = u32::from_be_bytes([entry[2], entry[3], entry[4], entry[5]]);
+                    (id, value)
+                })
+                .collect();
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_negotiates_configured_settings() {
+        let (client_io, mut server_io) = duplex(64 * 1024);
+
+        let config = Config {
+            max_concurrent_streams: Some(17),
+            max_frame_size: 32 * 1024,
+            max_header_list_size: 1024 * 1024,
+            header_table_size: 8192,
+            ..Config::default()
+        };
+
+        let (_dispatch_tx, req_rx) = crate::client::dispatch::channel();
+        tokio::spawn(async move {
+            let _ = handshake::<_, crate::Body>(client_io, req_rx, &config, Exec::default()).await;
+        });
+
+        let settings = read_client_settings(&mut server_io).await;
+
+        assert_eq!(settings.get(&SETTINGS_MAX_CONCURRENT_STREAMS), Some(&17));
+        assert_eq!(settings.get(&SETTINGS_MAX_FRAME_SIZE), Some(&(32 * 1024)));
+        assert_eq!(
+            settings.get(&SETTINGS_MAX_HEADER_LIST_SIZE),
+            Some(&(1024 * 1024))
+        );
+        assert_eq!(settings.get(&SETTINGS_HEADER_TABLE_SIZE), Some(&8192));
+    }
+}
@@ -0,0 +1,460 @@
+//! A small coordinator that multiplexes HTTP/2 PING frames.
+//!
+//! Both BDP (bandwidth-delay product) window estimation and keep-alive
+//! probing want to drive the connection's single `h2::PingPong` handle, but
+//! h2 only allows one PING to be in flight at a time. This module owns the
+//! handle on their behalf: it decides when a probe is needed, sends it, and
+//! once the matching PONG arrives, feeds the measured round-trip time to BDP
+//! sampling (whichever of the two sent the PING) and, for a keep-alive PING,
+//! restarts the keep-alive schedule.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use h2::{Ping, PingPong};
+use tokio::time::{Instant, Sleep};
+
+use crate::common::{task, Future, Pin, Poll};
+
+type WindowSize = u32;
+
+pub(super) fn disabled() -> Recorder {
+    Recorder { shared: None }
+}
+
+pub(super) fn channel(ping_pong: PingPong, config: Config) -> (Recorder, Ponger) {
+    debug_assert!(config.is_enabled());
+
+    let bdp = config.bdp_initial_window.map(|wnd| Bdp {
+        max_bandwidth: 0.0,
+        window_size: wnd,
+        bytes_since_ping: 0,
+    });
+
+    let keep_alive = config.keep_alive_interval.map(|interval| KeepAlive {
+        interval,
+        while_idle: config.keep_alive_while_idle,
+        state: KeepAliveState::Init,
+    });
+
+    let shared = Arc::new(Mutex::new(Shared {
+        ping_pong,
+        ping_sent_at: None,
+        ping_purpose: None,
+        ping_timeout: None,
+        // Also used as the deadline for BDP-only probes that have no
+        // keep-alive schedule of their own to borrow a timeout from.
+        ping_timeout_duration: config.keep_alive_timeout,
+        bdp,
+        keep_alive,
+        open_streams: 0,
+    }));
+
+    (
+        Recorder {
+            shared: Some(shared.clone()),
+        },
+        Ponger { shared: Some(shared) },
+    )
+}
+
+/// Handed out to the rest of the connection to report stream activity.
+#[derive(Clone)]
+pub(super) struct Recorder {
+    shared: Option<Arc<Mutex<Shared>>>,
+}
+
+/// Polled alongside the `h2::client::Connection` future; drives the
+/// PING/PONG state machine and reports results back to the caller.
+pub(super) struct Ponger {
+    shared: Option<Arc<Mutex<Shared>>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Config {
+    pub(crate) bdp_initial_window: Option<WindowSize>,
+    pub(crate) keep_alive_interval: Option<Duration>,
+    pub(crate) keep_alive_timeout: Duration,
+    pub(crate) keep_alive_while_idle: bool,
+}
+
+impl Config {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.bdp_initial_window.is_some() || self.keep_alive_interval.is_some()
+    }
+}
+
+pub(super) enum Ponged {
+    SizeUpdate(WindowSize),
+    KeepAliveTimedOut,
+}
+
+#[derive(Debug)]
+pub(super) struct KeepAliveTimedOut;
+
+impl std::fmt::Display for KeepAliveTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("keep-alive timed out")
+    }
+}
+
+impl std::error::Error for KeepAliveTimedOut {}
+
+struct Shared {
+    ping_pong: PingPong,
+    /// `None` when no PING is currently awaiting its PONG.
+    ping_sent_at: Option<Instant>,
+    /// Which subsystem asked for the currently outstanding PING, if any.
+    ping_purpose: Option<PingPurpose>,
+    /// Deadline for the currently outstanding PING. Armed alongside
+    /// `ping_sent_at` for *every* probe, BDP or keep-alive, so a lost pong
+    /// can never wedge the shared ping slot forever -- see `poll` below.
+    ping_timeout: Option<Pin<Box<Sleep>>>,
+    ping_timeout_duration: Duration,
+    bdp: Option<Bdp>,
+    keep_alive: Option<KeepAlive>,
+    open_streams: usize,
+}
+
+#[derive(Clone, Copy)]
+enum PingPurpose {
+    Bdp,
+    KeepAlive,
+}
+
+struct Bdp {
+    max_bandwidth: f64,
+    window_size: WindowSize,
+    bytes_since_ping: usize,
+}
+
+impl Bdp {
+    fn record_data(&mut self, len: usize) {
+        self.bytes_since_ping = self.bytes_since_ping.saturating_add(len);
+    }
+
+    /// Whether enough data has flowed since the last sample to make another
+    /// RTT measurement worthwhile, independent of any keep-alive schedule.
+    fn should_probe(&self) -> bool {
+        self.bytes_since_ping >= (self.window_size / 2) as usize
+    }
+
+    fn sample(&mut self, rtt: Duration) -> WindowSize {
+        self.bytes_since_ping = 0;
+        let rtt_ms = (rtt.as_millis() as f64).max(1.0);
+        let bandwidth = self.window_size as f64 / rtt_ms;
+        if bandwidth > self.max_bandwidth {
+            self.max_bandwidth = bandwidth;
+            self.window_size = self.window_size.saturating_mul(2).min(1 << 30);
+        }
+        self.window_size
+    }
+}
+
+struct KeepAlive {
+    interval: Duration,
+    while_idle: bool,
+    state: KeepAliveState,
+}
+
+enum KeepAliveState {
+    Init,
+    Scheduled(Pin<Box<Sleep>>),
+    /// Our ping has been sent; we're waiting on the shared `ping_sent_at` /
+    /// `ping_timeout` above to resolve it, one way or another.
+    PingSent,
+}
+
+impl Recorder {
+    /// Marks one more request stream as open; the count goes down again when
+    /// the returned [`RequestGuard`] is dropped. The client drops it once the
+    /// response head arrives, the request errors, or the caller cancels it;
+    /// a response body that is still streaming is not counted as open.
+    pub(super) fn start_request(&self) -> RequestGuard {
+        if let Some(ref shared) = self.shared {
+            shared.lock().unwrap().open_streams += 1;
+        }
+        RequestGuard {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Called by the response body as bytes are read off the wire, so BDP
+    /// sampling can decide for itself when it has seen enough data to take
+    /// another RTT measurement -- independent of the keep-alive schedule.
+    pub(super) fn record_data(&self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some(ref shared) = self.shared {
+            if let Some(ref mut bdp) = shared.lock().unwrap().bdp {
+                bdp.record_data(len);
+            }
+        }
+    }
+}
+
+pub(super) struct RequestGuard {
+    shared: Option<Arc<Mutex<Shared>>>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if let Some(ref shared) = self.shared {
+            shared.lock().unwrap().open_streams -= 1;
+        }
+    }
+}
+
+impl Ponger {
+    pub(super) fn poll(&mut self, cx: &mut task::Context<'_>) -> Poll<Ponged> {
+        match self.shared {
+            None => Poll::Pending,
+            Some(ref shared) => shared.lock().unwrap().poll(cx),
+        }
+    }
+}
+
+impl Shared {
+    fn poll(&mut self, cx: &mut task::Context<'_>) -> Poll<Ponged> {
+        if self.ping_sent_at.is_some() {
+            match self.ping_pong.poll_pong(cx) {
+                Poll::Ready(Ok(_pong)) => {
+                    let sent_at = self.ping_sent_at.take().unwrap();
+                    let purpose = self.ping_purpose.take().unwrap();
+                    self.ping_timeout = None;
+                    let rtt = Instant::now().saturating_duration_since(sent_at);
+
+                    if matches!(purpose, PingPurpose::KeepAlive) {
+                        if let Some(ref mut ka) = self.keep_alive {
+                            ka.state = KeepAliveState::Init;
+                        }
+                    }
+
+                    if let Some(wnd) = self.bdp.as_mut().map(|bdp| bdp.sample(rtt)) {
+                        // Run the keep-alive loop before returning so a pong that
+                        // only satisfies a BDP probe still leaves keep-alive with
+                        // a freshly armed `Sleep` (and registered waker), instead
+                        // of depending on some unrelated event to re-poll us.
+                        if let Poll::Ready(ponged) = self.poll_keep_alive(cx) {
+                            return Poll::Ready(ponged);
+                        }
+                        return Poll::Ready(Ponged::SizeUpdate(wnd));
+                    }
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!("ping_pong error: {}", e);
+                    self.ping_sent_at = None;
+                    self.ping_purpose = None;
+                    self.ping_timeout = None;
+                }
+                Poll::Pending => {
+                    if let Some(ponged) = self.poll_ping_timeout(cx) {
+                        return Poll::Ready(ponged);
+                    }
+                }
+            }
+        }
+
+        if self.ping_sent_at.is_none() {
+            if let Some(ref bdp) = self.bdp {
+                if bdp.should_probe() {
+                    trace!("sending BDP PING");
+                    self.send_ping(PingPurpose::Bdp);
+                }
+            }
+        }
+
+        self.poll_keep_alive(cx)
+    }
+
+    /// Checks the shared deadline for whichever PING is currently
+    /// outstanding. A timed-out keep-alive probe is reported as
+    /// `KeepAliveTimedOut`; a timed-out BDP-only probe just frees up the
+    /// shared ping slot so adaptive-window sampling isn't wedged forever by
+    /// one lost pong.
+    fn poll_ping_timeout(&mut self, cx: &mut task::Context<'_>) -> Option<Ponged> {
+        let timed_out = match self.ping_timeout {
+            Some(ref mut timeout) => timeout.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if !timed_out {
+            return None;
+        }
+
+        let purpose = self.ping_purpose.take();
+        self.ping_sent_at = None;
+        self.ping_timeout = None;
+
+        match purpose {
+            Some(PingPurpose::KeepAlive) => Some(Ponged::KeepAliveTimedOut),
+            Some(PingPurpose::Bdp) | None => {
+                trace!("BDP ping timed out waiting for pong, will probe again later");
+                None
+            }
+        }
+    }
+
+    fn send_ping(&mut self, purpose: PingPurpose) {
+        match self.ping_pong.send_ping(Ping::opaque()) {
+            Ok(()) => {
+                self.ping_sent_at = Some(Instant::now());
+                self.ping_purpose = Some(purpose);
+                self.ping_timeout = Some(Box::pin(tokio::time::sleep(self.ping_timeout_duration)));
+            }
+            Err(e) => debug!("failed to send ping: {}", e),
+        }
+    }
+
+    fn poll_keep_alive(&mut self, cx: &mut task::Context<'_>) -> Poll<Ponged> {
+        let ka = match self.keep_alive {
+            Some(ref mut ka) => ka,
+            None => return Poll::Pending,
+        };
+
+        loop {
+            match ka.state {
+                KeepAliveState::Init => {
+                    ka.state = KeepAliveState::Scheduled(Box::pin(tokio::time::sleep(ka.interval)));
+                }
+                KeepAliveState::Scheduled(ref mut sleep) => {
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    if self.open_streams == 0 && !ka.while_idle {
+                        ka.state = KeepAliveState::Init;
+                        continue;
+                    }
+                    if self.ping_sent_at.is_some() {
+                        // A BDP probe is already in flight; h2 only allows one
+                        // PING outstanding at a time. Don't just wait on that
+                        // probe to come back -- it has its own independent
+                        // timeout, but if it's simply slow we still want our
+                        // own schedule armed and ticking rather than stalled
+                        // with no timer at all.
+                        ka.state = KeepAliveState::Init;
+                        continue;
+                    }
+                    trace!("sending keep-alive PING");
+                    self.send_ping(PingPurpose::KeepAlive);
+                    if self.ping_sent_at.is_none() {
+                        // send_ping failed; try again next interval instead of
+                        // leaving no timer armed at all.
+                        ka.state = KeepAliveState::Init;
+                        continue;
+                    }
+                    ka.state = KeepAliveState::PingSent;
+                }
+                KeepAliveState::PingSent => {
+                    // The shared `ping_timeout` polled at the top of `Shared::poll`
+                    // is what actually wakes us up; nothing further to arm here.
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Context;
+
+    use futures_util::task::noop_waker_ref;
+    use h2::client::handshake;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::*;
+
+    fn poll_once(ponger: &mut Ponger) -> Poll<Ponged> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        ponger.poll(&mut cx)
+    }
+
+    async fn test_ping_pong() -> (PingPong, tokio::io::DuplexStream) {
+        let (client_io, server_io) = duplex(64 * 1024);
+        let (h2_client, mut conn) = handshake(client_io).await.expect("client handshake");
+        let ping_pong = conn.ping_pong().expect("ping_pong");
+        tokio::spawn(async move {
+            // keep a request handle alive so the connection isn't closed for lack of users
+            let _h2_client = h2_client;
+            let _ = conn.await;
+        });
+        (ping_pong, server_io)
+    }
+
+    /// Drives a BDP probe in flight concurrently with a matured keep-alive
+    /// schedule and asserts the connection still times out, instead of the
+    /// keep-alive timer silently wedging on the outstanding BDP pong.
+    #[tokio::test(start_paused = true)]
+    async fn keep_alive_times_out_while_a_bdp_probe_is_in_flight() {
+        let (ping_pong, mut server_io) = test_ping_pong().await;
+
+        // Never reply on the server side: every PING this test sends (BDP or
+        // keep-alive) goes unanswered, simulating a silently half-open peer.
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            loop {
+                if server_io.read(&mut buf).await.unwrap_or(0) == 0 {
+                    break;
+                }
+            }
+        });
+
+        let config = Config {
+            bdp_initial_window: Some(64 * 1024),
+            keep_alive_interval: Some(Duration::from_millis(50)),
+            keep_alive_timeout: Duration::from_millis(50),
+            keep_alive_while_idle: true,
+        };
+        let (recorder, mut ponger) = channel(ping_pong, config);
+
+        // Put a BDP probe in flight by recording enough bytes to cross
+        // `should_probe`'s threshold, then polling once so `Shared::poll`
+        // actually sends it.
+        recorder.record_data(64 * 1024);
+        assert!(matches!(poll_once(&mut ponger), Poll::Pending));
+
+        // Let the keep-alive interval mature while that BDP ping is still
+        // outstanding (nothing ever answers it). Before this fix, keep-alive
+        // would defer to `Init` here without looping back to arm a fresh
+        // `Sleep`, so it would never be polled again.
+        tokio::time::advance(Duration::from_millis(60)).await;
+        assert!(matches!(poll_once(&mut ponger), Poll::Pending));
+
+        // The BDP ping's own timeout fires first and frees the shared slot;
+        // the re-armed keep-alive schedule then sends its own ping and times
+        // out in turn.
+        tokio::time::advance(Duration::from_millis(60)).await;
+        assert!(matches!(poll_once(&mut ponger), Poll::Pending));
+
+        tokio::time::advance(Duration::from_millis(60)).await;
+        let result = poll_once(&mut ponger);
+        assert!(
+            matches!(result, Poll::Ready(Ponged::KeepAliveTimedOut)),
+            "expected keep-alive to time out the dead connection"
+        );
+    }
+
+    #[tokio::test]
+    async fn request_guard_decrements_open_streams_on_drop() {
+        let (ping_pong, _server_io) = test_ping_pong().await;
+
+        let config = Config {
+            bdp_initial_window: None,
+            keep_alive_interval: Some(Duration::from_secs(3600)),
+            keep_alive_timeout: Duration::from_secs(20),
+            keep_alive_while_idle: false,
+        };
+        let (recorder, _ponger) = channel(ping_pong, config);
+
+        let guard_a = recorder.start_request();
+        let guard_b = recorder.start_request();
+        assert_eq!(recorder.shared.as_ref().unwrap().lock().unwrap().open_streams, 2);
+
+        drop(guard_a);
+        assert_eq!(recorder.shared.as_ref().unwrap().lock().unwrap().open_streams, 1);
+
+        drop(guard_b);
+        assert_eq!(recorder.shared.as_ref().unwrap().lock().unwrap().open_streams, 0);
+    }
+}